@@ -1,9 +1,19 @@
 use clap::Parser;
+use futures::executor::block_on;
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request as HyperRequest, Response as HyperResponse, Server as HyperServer};
 use log::info;
+use prometheus::{Encoder, Gauge, IntCounter, Registry, TextEncoder};
+use std::collections::hash_map::DefaultHasher;
+use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
 use std::io::Write;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::num::NonZeroU32;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tonic::{transport::Server, Request, Response, Status};
 
 // Reuse protobuf definitions
@@ -25,12 +35,188 @@ struct Args {
     /// Rate limit (events/sec) for input generator. 0 = unlimited.
     #[arg(short, long, default_value_t = 0)]
     rate: u64,
+
+    /// Bandwidth cap (bytes/sec) for the input generator's socket writes. 0 = unlimited.
+    #[arg(long, default_value_t = 0)]
+    byte_rate: u64,
+
+    /// Number of concurrent input-generator worker threads, each with its own socket connection.
+    #[arg(short, long, default_value_t = 1)]
+    workers: u32,
+
+    /// Port to serve Prometheus metrics on at /metrics.
+    #[arg(long, default_value_t = 9464)]
+    metrics_port: u16,
+
+    /// Minimum acceptable server throughput (events/sec) before the stall watchdog engages. 0 = disabled.
+    #[arg(long, default_value_t = 0)]
+    min_throughput: u64,
+
+    /// How long throughput may stay below `--min-throughput` before the watchdog flags a stall.
+    #[arg(long, default_value_t = 5)]
+    grace_period: u64,
+}
+
+// Prometheus metrics, gathered from the existing atomic counters each stats-reporter tick.
+struct Metrics {
+    registry: Registry,
+    received_total: IntCounter,
+    sent_total: IntCounter,
+    events_per_sec: Gauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let received_total = IntCounter::new(
+            "sensor_events_received_total",
+            "Total metrics received by the mock gRPC server",
+        )
+        .unwrap();
+        let sent_total = IntCounter::new(
+            "generator_alert_lines_sent_total",
+            "Total alert lines written by the input generator",
+        )
+        .unwrap();
+        let events_per_sec = Gauge::new(
+            "sensor_events_per_second",
+            "Current server throughput in events/sec",
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(received_total.clone()))
+            .unwrap();
+        registry.register(Box::new(sent_total.clone())).unwrap();
+        registry
+            .register(Box::new(events_per_sec.clone()))
+            .unwrap();
+
+        Self {
+            registry,
+            received_total,
+            sent_total,
+            events_per_sec,
+        }
+    }
+}
+
+async fn serve_metrics(
+    req: HyperRequest<Body>,
+    metrics: Arc<Metrics>,
+) -> Result<HyperResponse<Body>, Infallible> {
+    if req.uri().path() != "/metrics" {
+        return Ok(HyperResponse::builder()
+            .status(404)
+            .body(Body::from("not found"))
+            .unwrap());
+    }
+
+    let encoder = TextEncoder::new();
+    let metric_families = metrics.registry.gather();
+    let mut buffer = Vec::new();
+    encoder.encode(&metric_families, &mut buffer).unwrap();
+
+    Ok(HyperResponse::builder()
+        .status(200)
+        .header("Content-Type", encoder.format_type())
+        .body(Body::from(buffer))
+        .unwrap())
+}
+
+fn start_metrics_server(port: u16, metrics: Arc<Metrics>) {
+    tokio::spawn(async move {
+        let addr = ([0, 0, 0, 0], port).into();
+        let make_svc = make_service_fn(move |_conn| {
+            let metrics = metrics.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| serve_metrics(req, metrics.clone())))
+            }
+        });
+
+        info!("Prometheus metrics endpoint listening on 0.0.0.0:{}", port);
+        if let Err(e) = HyperServer::bind(&addr).serve(make_svc).await {
+            log::error!("Metrics server error: {}", e);
+        }
+    });
+}
+
+/// Constant-memory cardinality estimator. Tracks how many *distinct* events reach the
+/// server without storing every id, so drops/dedup can be verified at multi-million scale.
+struct HyperLogLog {
+    registers: Vec<u8>,
+    p: u32,
+}
+
+impl HyperLogLog {
+    const P: u32 = 14;
+
+    fn new() -> Self {
+        Self {
+            registers: vec![0u8; 1 << Self::P],
+            p: Self::P,
+        }
+    }
+
+    fn add<T: Hash>(&mut self, value: &T) {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let significant_bits = 64 - self.p;
+        let index = (hash >> significant_bits) as usize;
+        let remaining = hash << self.p;
+        // Cap at significant_bits + 1: leading_zeros() over the full 64-bit word
+        // overcounts when the significant bits are all zero, since the low `p` padding
+        // bits introduced by the shift are zero too.
+        let rank = (remaining.leading_zeros() + 1).min(significant_bits + 1) as u8;
+
+        let register = &mut self.registers[index];
+        if rank > *register {
+            *register = rank;
+        }
+    }
+
+    fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha_m = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|&register| 2f64.powi(-(register as i32)))
+            .sum();
+        let raw_estimate = alpha_m * m * m / sum;
+
+        if raw_estimate <= 2.5 * m {
+            let zero_registers = self.registers.iter().filter(|&&r| r == 0).count();
+            if zero_registers > 0 {
+                return m * (m / zero_registers as f64).ln();
+            }
+        }
+        raw_estimate
+    }
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 // Mock Server
 #[derive(Default)]
 pub struct MockSensorService {
     counter: Arc<AtomicU64>,
+    // Set by the throughput watchdog when it decides the client itself has stalled.
+    stalled: Arc<AtomicBool>,
+    unique_events: Arc<Mutex<HyperLogLog>>,
 }
 
 #[tonic::async_trait]
@@ -42,7 +228,25 @@ impl SensorService for MockSensorService {
         info!("Server: Accepted stream connection");
         let mut stream = request.into_inner();
         let mut count = 0;
-        while let Some(event) = stream.message().await? {
+        loop {
+            // Race the next message against a short poll of the watchdog flag, so a
+            // client that has gone completely silent (no more messages to trigger a
+            // loop iteration at all) still gets its stream killed, not just a slow one.
+            let event = tokio::select! {
+                message = stream.message() => match message? {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = tokio::time::sleep(Duration::from_millis(200)) => {
+                    if self.stalled.load(Ordering::Relaxed) {
+                        return Err(Status::deadline_exceeded(
+                            "watchdog: client stalled below minimum throughput",
+                        ));
+                    }
+                    continue;
+                }
+            };
+
             count += 1;
             if count % 1000 == 0 {
                 info!(
@@ -51,17 +255,130 @@ impl SensorService for MockSensorService {
                     event.metrics.len()
                 );
             }
-            // Count metrics in the event
+            // Count metrics in the event. This must happen before the stall check below:
+            // the watchdog clears `stalled` once `counter` shows throughput recovering, so
+            // every stream (including a brand-new one) needs to get its events counted
+            // before it can be killed, or `counter` would stay flat forever and the
+            // watchdog could never un-stall.
             self.counter
                 .fetch_add(event.metrics.len() as u64, Ordering::Relaxed);
+
+            // Hash the event's flow id directly instead of Debug-formatting/allocating
+            // the whole event on every message of the hot receive path.
+            self.unique_events.lock().unwrap().add(&event.flow_id);
+
+            if self.stalled.load(Ordering::Relaxed) {
+                return Err(Status::deadline_exceeded(
+                    "watchdog: client stalled below minimum throughput",
+                ));
+            }
         }
         info!("Server: Stream ended");
         Ok(Response::new(()))
     }
 }
 
+// Byte-level token bucket for capping socket write bandwidth.
+struct ByteRateLimiter {
+    capacity_bytes: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl ByteRateLimiter {
+    fn new(bytes_per_sec: u64) -> Self {
+        let capacity_bytes = bytes_per_sec as f64;
+        Self {
+            capacity_bytes,
+            refill_per_sec: capacity_bytes,
+            tokens: capacity_bytes,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Blocks until `amount` bytes worth of tokens are available, then consumes them.
+    fn consume(&mut self, amount: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity_bytes);
+
+        let needed = amount as f64;
+        if needed > self.tokens {
+            let shortfall = needed - self.tokens;
+            std::thread::sleep(Duration::from_secs_f64(shortfall / self.refill_per_sec));
+            self.tokens = 0.0;
+            self.last_refill = Instant::now();
+        } else {
+            self.tokens -= needed;
+        }
+    }
+}
+
+/// Wraps a `UnixStream` and throttles `write_all` against a `--byte-rate` bucket shared by
+/// every input-generator worker, so the configured bytes/sec stays one global ceiling
+/// regardless of `--workers`.
+struct RateLimitedStream {
+    inner: UnixStream,
+    limiter: Option<Arc<Mutex<ByteRateLimiter>>>,
+}
+
+impl RateLimitedStream {
+    fn new(inner: UnixStream, limiter: Option<Arc<Mutex<ByteRateLimiter>>>) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+impl Write for RateLimitedStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        if let Some(limiter) = &self.limiter {
+            limiter.lock().unwrap().consume(buf.len());
+        }
+        self.inner.write_all(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 // Input Generator
-fn start_input_generator(socket_path: String, rate: u64) {
+fn start_input_generator(
+    socket_path: String,
+    rate: u64,
+    byte_rate: u64,
+    workers: u32,
+    sent_counter: Arc<AtomicU64>,
+) {
+    // --rate/--byte-rate are global caps. A fixed quota can't be enforced correctly by
+    // splitting it numerically across N independently-sleeping threads (rounding alone
+    // breaks small rates), so every worker shares the same limiter instance instead.
+    let limiter = NonZeroU32::new(rate as u32)
+        .map(|rate| Arc::new(RateLimiter::direct(Quota::per_second(rate))));
+    let byte_limiter =
+        (byte_rate > 0).then(|| Arc::new(Mutex::new(ByteRateLimiter::new(byte_rate))));
+
+    for worker_id in 0..workers {
+        let socket_path = socket_path.clone();
+        let sent_counter = sent_counter.clone();
+        let limiter = limiter.clone();
+        let byte_limiter = byte_limiter.clone();
+        spawn_input_generator_worker(socket_path, limiter, byte_limiter, worker_id, sent_counter);
+    }
+}
+
+fn spawn_input_generator_worker(
+    socket_path: String,
+    limiter: Option<Arc<DefaultDirectRateLimiter>>,
+    byte_limiter: Option<Arc<Mutex<ByteRateLimiter>>>,
+    worker_id: u32,
+    sent_counter: Arc<AtomicU64>,
+) {
     std::thread::spawn(move || {
         // Create the socket listener if it doesn't exist, to ensure we can connect to it?
         // No, the CLIENT (sensor-service) creates the listener.
@@ -79,8 +396,8 @@ fn start_input_generator(socket_path: String, rate: u64) {
 
         // Connect to socket
         let mut stream = loop {
-            match std::os::unix::net::UnixStream::connect(&socket_path) {
-                Ok(s) => break s,
+            match UnixStream::connect(&socket_path) {
+                Ok(s) => break RateLimitedStream::new(s, byte_limiter.clone()),
                 Err(_) => {
                     std::thread::sleep(Duration::from_millis(500));
                 }
@@ -93,9 +410,9 @@ fn start_input_generator(socket_path: String, rate: u64) {
         let alert_json = r#"{"metadata":{"sensor_id":"test","sensor_version":"1.0","sent_at":0,"hash_sha256":"hash","read_at":0,"received_at":0},"timestamp":"2023-10-27T10:00:00.000000+0000","flow_id":123456789,"in_iface":"eth0","event_type":"alert","src_ip":"192.168.1.10","src_port":12345,"dest_ip":"10.0.0.1","dest_port":80,"proto":"TCP","alert":{"action":"allowed","gid":1,"signature_id":1000001,"rev":1,"signature":"Test Alert","category":"Misc","severity":3},"http":{"hostname":"example.com","url":"/","http_user_agent":"Mozilla/5.0","http_content_type":"text/html","http_method":"GET","protocol":"HTTP/1.1","status":200,"length":1024},"app_proto":"http","flow":{"pkts_toserver":10,"pkts_toclient":10,"bytes_toserver":1000,"bytes_toclient":5000,"start":"2023-10-27T10:00:00.000000+0000"}}"#;
         let alert_line = format!("{}\n", alert_json);
 
-        let mut count = 0;
-        let mut flow_id_counter: u64 = 0;
-        let start = std::time::Instant::now();
+        // Seed each worker's counter into a disjoint range so the signature_id values it
+        // emits stay globally unique across workers (the JSON's flow_id field is fixed).
+        let mut flow_id_counter: u64 = (worker_id as u64) << 40;
 
         loop {
             flow_id_counter = flow_id_counter.wrapping_add(1);
@@ -108,32 +425,23 @@ fn start_input_generator(socket_path: String, rate: u64) {
             let alert_line = format!("{}\n", alert_json);
             let bytes = alert_line.as_bytes();
 
-            if let Err(_) = stream.write_all(bytes) {
-                // Reconnect if failed
-                if let Ok(s) = std::os::unix::net::UnixStream::connect(&socket_path) {
-                    stream = s;
-                } else {
-                    std::thread::sleep(Duration::from_millis(100));
+            match stream.write_all(bytes) {
+                Ok(()) => {
+                    sent_counter.fetch_add(1, Ordering::Relaxed);
                 }
-            }
-
-            if rate > 0 {
-                count += 1;
-                if count >= rate {
-                    let elapsed = start.elapsed();
-                    if elapsed < Duration::from_secs(1) {
-                        std::thread::sleep(Duration::from_secs(1) - elapsed);
+                Err(_) => {
+                    // Reconnect if failed
+                    if let Ok(s) = UnixStream::connect(&socket_path) {
+                        stream = RateLimitedStream::new(s, byte_limiter.clone());
+                    } else {
+                        std::thread::sleep(Duration::from_millis(100));
                     }
-                    count = 0;
-                    // Reset start? No, this logic is a bit flawed for precise rate limiting.
-                    // Simple token bucket or just sleep per batch would be better.
-                    // For now, let's just sleep a tiny bit if rate is set.
-                    // Actually, let's ignore precise rate limiting for now as the goal is usually max throughput.
-                    // If rate > 0, we sleep 1s / rate.
-                    let sleep_ns = 1_000_000_000 / rate;
-                    std::thread::sleep(Duration::from_nanos(sleep_ns));
                 }
             }
+
+            if let Some(limiter) = &limiter {
+                block_on(limiter.until_ready());
+            }
         }
     });
 }
@@ -151,8 +459,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Start Mock Server
     let addr = format!("0.0.0.0:{}", args.port).parse()?;
     let counter = Arc::new(AtomicU64::new(0));
+    let stalled = Arc::new(AtomicBool::new(false));
+    let unique_events = Arc::new(Mutex::new(HyperLogLog::new()));
     let service = MockSensorService {
         counter: counter.clone(),
+        stalled: stalled.clone(),
+        unique_events: unique_events.clone(),
     };
 
     info!("Mock gRPC Server listening on {}", addr);
@@ -166,21 +478,77 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .unwrap();
     });
 
+    // Start Metrics Endpoint
+    let metrics = Arc::new(Metrics::new());
+    start_metrics_server(args.metrics_port, metrics.clone());
+
     // Start Stats Reporter
+    let sent_counter = Arc::new(AtomicU64::new(0));
+    let sent_counter_clone = sent_counter.clone();
+    let mut stalled_since: Option<Instant> = None;
     tokio::spawn(async move {
         let mut last_count = 0;
+        let mut last_sent = 0;
         loop {
             tokio::time::sleep(Duration::from_secs(1)).await;
             let current_count = counter_clone.load(Ordering::Relaxed);
             let rate = current_count - last_count;
             info!("Server Throughput: {} events/sec", rate);
+            metrics.received_total.inc_by(rate);
+            metrics.events_per_sec.set(rate as f64);
             last_count = current_count;
+
+            let current_sent = sent_counter_clone.load(Ordering::Relaxed);
+            let sent_rate = current_sent - last_sent;
+            info!("Generator Throughput: {} events/sec", sent_rate);
+            metrics.sent_total.inc_by(sent_rate);
+            last_sent = current_sent;
+
+            let estimated_unique = unique_events.lock().unwrap().estimate();
+            info!("Estimated unique events: {:.0}", estimated_unique);
+
+            // Minimum-throughput watchdog: only alarm on a client-side stall, not one
+            // caused by the generator itself drying up upstream.
+            if args.min_throughput > 0 {
+                if rate < args.min_throughput {
+                    let since = *stalled_since.get_or_insert_with(Instant::now);
+                    let stalled_for = since.elapsed();
+                    if stalled_for >= Duration::from_secs(args.grace_period) {
+                        if sent_rate < args.min_throughput {
+                            log::debug!(
+                                "Watchdog: generator is also stalled ({} events/sec); not a client stall",
+                                sent_rate
+                            );
+                            stalled.store(false, Ordering::Relaxed);
+                        } else {
+                            log::warn!(
+                                "client stalled: {} events/sec for {}s",
+                                rate,
+                                stalled_for.as_secs()
+                            );
+                            stalled.store(true, Ordering::Relaxed);
+                        }
+                    }
+                } else {
+                    stalled_since = None;
+                    stalled.store(false, Ordering::Relaxed);
+                }
+            }
         }
     });
 
     // Start Input Generator
-    info!("Starting Input Generator targeting {}", args.socket);
-    start_input_generator(args.socket, args.rate);
+    info!(
+        "Starting Input Generator targeting {} with {} worker(s)",
+        args.socket, args.workers
+    );
+    start_input_generator(
+        args.socket,
+        args.rate,
+        args.byte_rate,
+        args.workers,
+        sent_counter,
+    );
 
     // Keep main alive
     tokio::signal::ctrl_c().await?;